@@ -1,8 +1,10 @@
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
+use std::ops::ControlFlow;
 use std::path;
 use std::str;
 
@@ -22,48 +24,166 @@ macro_rules! try_with_context {
     }
 }
 
+/// Magic bytes identifying a goldfish log file, written at offset 0.
+const MAGIC: &[u8; 8] = b"GOLDFISH";
+
+/// Current on-disk format version.
+///
+/// Bumped to 2 when each frame grew a kind byte (see [`write_entry`])
+/// distinguishing batch markers from ordinary entries by their frame
+/// instead of by payload bytes. There is no migration between versions —
+/// see [`Log::load`].
+const VERSION: u8 = 2;
+
+/// Set when every entry's payload is followed by a CRC32 (true since version 1).
+const FLAG_CRC32: u8 = 0b0000_0001;
+
+/// Reserved for a future alternative length encoding; unset means the
+/// reverse-readable varint framing used since version 1.
+#[allow(dead_code)]
+const FLAG_WIDE_LEN: u8 = 0b0000_0010;
+
+/// Length in bytes of the `[magic][version][flags]` header every log file
+/// starts with. Iteration never reads past this offset.
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 2;
+
+/// Continuation bit set on every varint length byte except the terminator.
+const VARINT_CONTINUE: u8 = 0b1000_0000;
+
 #[derive(Debug)]
 pub struct Log {
     path: path::PathBuf,
     file: fs::File,
 }
 
+/// Direction in which to walk a log's entries with [`Log::for_each_entry`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the oldest entry to the newest.
+    Forward,
+    /// From the newest entry to the oldest.
+    Backward,
+}
+
 impl Log {
     /// Load the log file at `path`, or create one if it doesn't exist.
     ///
-    /// WARNING: this function does **not** verify that `path` is a valid log file.
+    /// A fresh file is given a header identifying it as a goldfish log and
+    /// recording the format version. An existing file has its header
+    /// validated so that incompatible or foreign files are rejected instead
+    /// of being silently misread. There is no migration between format
+    /// versions: a log written by an older (or newer) version fails to load
+    /// with a clear error rather than being reinterpreted under the current
+    /// one; pass a different `--dir`/`--cache` or delete the old file to
+    /// start fresh.
     pub fn load(path: path::PathBuf) -> anyhow::Result<Self> {
         if let Some(parent) = path.parent() {
             fs::create_dir_all(&parent)
                 .with_context(|| anyhow!("Could not create directory: '{}'", parent.display()))?;
         }
 
-        fs::OpenOptions::new()
+        let mut file = fs::OpenOptions::new()
             .read(true)
             .append(true)
             .create(true)
             .open(&path)
-            .with_context(|| anyhow!("Could not open log file: '{}'", path.display()))
-            .map(|file| Log { file, path })
+            .with_context(|| anyhow!("Could not open log file: '{}'", path.display()))?;
+
+        let len = file
+            .metadata()
+            .with_context(|| anyhow!("Could not read log file metadata: '{}'", path.display()))?
+            .len();
+
+        if len == 0 {
+            write_header(&mut file)
+                .with_context(|| anyhow!("Could not write log file header: '{}'", path.display()))?;
+        } else {
+            read_header(&mut file)
+                .with_context(|| anyhow!("Could not read log file header: '{}'", path.display()))?;
+        }
+
+        Ok(Log { file, path })
     }
 
-    /// Append `entry` to the underlying log file.
+    /// Append `entry` to the underlying log file, framed with a CRC32 of the
+    /// payload so corruption can be detected during backward iteration.
     pub fn append<E: AsRef<[u8]>>(&mut self, entry: E) -> anyhow::Result<()> {
-        try_with_context! {
-            CONTEXT: anyhow!("Could not append to log file: `{}`", self.path.display());
-            let entry = entry.as_ref();
-            self.file.write_all(entry)?;
-            self.file.write_u16::<LittleEndian>(entry.len() as u16)?;
-        }
+        write_entry(&mut self.file, KIND_ENTRY, entry.as_ref())
+            .with_context(|| anyhow!("Could not append to log file: `{}`", self.path.display()))
     }
-    
-    /// Clear the underlying log file.
+
+    /// Clear the underlying log file, leaving only the header behind.
     pub fn clear(&mut self) -> anyhow::Result<()> {
         try_with_context! {
             CONTEXT: anyhow!("Could not clear log file: `{}`", self.path.display());
             let _ = self.file.seek(io::SeekFrom::Start(0))?;
             self.file.set_len(0)?;
+            write_header(&mut self.file)?;
+        }
+    }
+
+    /// Atomically rewrite the log to contain only `entries`, in that order.
+    ///
+    /// The header and entries are written into a sibling `<path>.compact`
+    /// file, durably synced, then renamed over `path`. Renaming within a
+    /// directory is atomic on POSIX, so a crash at any point during
+    /// compaction leaves either the complete old log or the complete new
+    /// one in place — never a half-written file. This is the default
+    /// compaction strategy used by `clean`/`get`; see [`Log::compact_in_place`]
+    /// for the faster, crash-unsafe alternative.
+    pub fn compact<E, I>(&mut self, entries: I) -> anyhow::Result<()>
+    where
+        E: AsRef<[u8]>,
+        I: IntoIterator<Item = E>,
+    {
+        let temp_path = self.path.with_extension("compact");
+
+        let mut temp_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)
+            .with_context(|| anyhow!("Could not create compaction file: '{}'", temp_path.display()))?;
+
+        (|| -> io::Result<()> {
+            write_header(&mut temp_file)?;
+            for entry in entries {
+                write_entry(&mut temp_file, KIND_ENTRY, entry.as_ref())?;
+            }
+            temp_file.sync_data()
+        })()
+        .with_context(|| anyhow!("Could not write compaction file: '{}'", temp_path.display()))?;
+
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| anyhow!("Could not replace log file: '{}'", self.path.display()))?;
+
+        self.file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| anyhow!("Could not reopen log file: '{}'", self.path.display()))?;
+
+        Ok(())
+    }
+
+    /// Rewrite the log in place to contain only `entries`, in that order.
+    ///
+    /// Unlike [`Log::compact`], this truncates the existing file and
+    /// re-appends directly, so a crash between the truncate and the last
+    /// append loses every entry that hadn't been re-written yet. Only use
+    /// this when that risk is acceptable in exchange for avoiding the
+    /// temp file and rename.
+    pub fn compact_in_place<E, I>(&mut self, entries: I) -> anyhow::Result<()>
+    where
+        E: AsRef<[u8]>,
+        I: IntoIterator<Item = E>,
+    {
+        self.clear()?;
+        for entry in entries {
+            self.append(entry)?;
         }
+        self.sync()
     }
 
     /// Delete the underlying log file.
@@ -81,27 +201,272 @@ impl Log {
         }
     }
 
+    /// Append `entries` as a single atomic batch: a begin marker, each
+    /// entry, then an end marker recording how many entries it covers,
+    /// followed by one `sync`.
+    ///
+    /// If the process crashes partway through, the begin marker is left
+    /// without a matching end marker; [`Log::entries`] and [`Log::recover`]
+    /// both recognize this and treat the whole incomplete batch as if it
+    /// had never been written.
+    pub fn append_batch<E, I>(&mut self, entries: I) -> anyhow::Result<()>
+    where
+        E: AsRef<[u8]>,
+        I: IntoIterator<Item = E>,
+    {
+        (|| -> io::Result<()> {
+            write_entry(&mut self.file, KIND_BATCH_BEGIN, &[])?;
+
+            let mut count: u32 = 0;
+            for entry in entries {
+                write_entry(&mut self.file, KIND_ENTRY, entry.as_ref())?;
+                count = count.checked_add(1).expect("batch has more than u32::MAX entries");
+            }
+
+            write_entry(&mut self.file, KIND_BATCH_END, &count.to_le_bytes())
+        })()
+        .with_context(|| anyhow!("Could not append batch to log file: `{}`", self.path.display()))?;
+
+        self.sync()
+    }
+
+    /// Walk the log's entries in `dir`, calling `f` with each raw entry and
+    /// the frame kind tag it was written with (so batch markers can be told
+    /// apart from ordinary entries; see [`classify`]) until `f` returns
+    /// [`ControlFlow::Break`] or there are none left to visit.
+    ///
+    /// Walking backward, an entry whose frame is malformed or whose CRC32
+    /// doesn't match also stops the walk, same as `f` breaking — this format
+    /// can only be decoded back-to-front, so nothing past a bad entry can be
+    /// trusted either. An actual I/O failure, as opposed to malformed or
+    /// mismatched framing, is not treated as a stopping point to recover
+    /// from; it's returned as an error instead, since it says nothing about
+    /// whether the data on disk is actually corrupt. Walking forward
+    /// requires a preliminary backward pass to find where each entry starts
+    /// (the length trails the payload), but only the offsets, lengths and
+    /// kinds are kept in memory, not the decoded entries. If that pass hits
+    /// malformed or mismatched framing, only the entries newer than it are
+    /// visited.
+    ///
+    /// Returns the offset the walk stopped at early: the start of the entry
+    /// `f` broke on or, walking backward, where malformed or mismatched
+    /// framing was found. `None` means the walk ran to its end.
+    /// [`Log::recover`] uses this to find where to truncate.
+    pub fn for_each_entry(
+        &mut self,
+        dir: Direction,
+        mut f: impl FnMut(u8, &[u8]) -> io::Result<ControlFlow<()>>,
+    ) -> anyhow::Result<Option<u64>> {
+        match dir {
+        | Direction::Backward => {
+            let mut iter = self.iter();
+            loop {
+                match iter.prev() {
+                | Ok(Some((kind, entry))) => match f(kind, entry)? {
+                    | ControlFlow::Continue(()) => continue,
+                    | ControlFlow::Break(()) => return Ok(Some(iter.pos)),
+                },
+                | Ok(None) => return Ok(None),
+                | Err(Error::Corrupt { .. }) => return Ok(Some(iter.pos)),
+                | Err(Error::Io(error)) => return Err(error)
+                    .with_context(|| anyhow!("Could not read log file: `{}`", self.path.display())),
+                }
+            }
+        }
+        | Direction::Forward => {
+            let mut spans = Vec::new();
+            {
+                let mut iter = self.iter();
+                loop {
+                    match iter.prev() {
+                    | Ok(Some((kind, entry))) => spans.push((iter.pos, entry.len() as u64, kind)),
+                    | Ok(None) | Err(Error::Corrupt { .. }) => break,
+                    | Err(Error::Io(error)) => return Err(error)
+                        .with_context(|| anyhow!("Could not read log file: `{}`", self.path.display())),
+                    }
+                }
+            }
+
+            let mut buf = Vec::new();
+            for (start, len, kind) in spans.into_iter().rev() {
+                self.file
+                    .seek(io::SeekFrom::Start(start))
+                    .with_context(|| anyhow!("Could not seek in log file: `{}`", self.path.display()))?;
+                buf.resize(len as usize, 0);
+                self.file
+                    .read_exact(&mut buf)
+                    .with_context(|| anyhow!("Could not read log file: `{}`", self.path.display()))?;
+
+                if let ControlFlow::Break(()) = f(kind, &buf)? {
+                    return Ok(Some(start));
+                }
+            }
+
+            Ok(None)
+        }
+        }
+    }
+
     /// Return an ordered set of the latest entries in the log.
+    ///
+    /// Entries belonging to a batch only count once the batch's begin
+    /// marker has been reached; an incomplete trailing batch (end marker
+    /// missing, because the process crashed before writing it) is dropped
+    /// in its entirety, as is a corrupt trailing entry.
     pub fn entries(&mut self, count: usize) -> anyhow::Result<IndexSet<String>> {
+        self.for_each_unique_entry(count, |_| Ok(()))
+    }
+
+    /// Like [`Log::entries`], but calls `f` with each unique entry, in the
+    /// same most-recent-first order `entries` would return them, as soon as
+    /// it's found instead of collecting the full set first.
+    ///
+    /// The returned set is still the complete result, since `clean` and
+    /// `get`'s own compaction need it afterward; the benefit is that a
+    /// caller like `get` can start printing immediately instead of
+    /// buffering everything before writing to `stdout`.
+    ///
+    /// A trailing batch that was never closed with an end marker looks,
+    /// byte for byte, like ordinary standalone entries until its begin
+    /// marker is reached — the end marker that would identify them as part of
+    /// an incomplete batch is exactly the one a crash leaves unwritten. So
+    /// this runs [`Log::recover`] first to excise any such trailing batch
+    /// (and any other trailing corruption) before reading, rather than
+    /// risk surfacing torn data as if it had been committed.
+    pub fn for_each_unique_entry(
+        &mut self,
+        count: usize,
+        mut f: impl FnMut(&str) -> io::Result<()>,
+    ) -> anyhow::Result<IndexSet<String>> {
+        self.recover()?;
+
         let mut cache = IndexSet::with_capacity(count);
-        let mut iter = self.iter();
+        let mut batch_remaining: Option<u32> = None;
+        let mut batch_entries: Vec<String> = Vec::new();
 
-        // Scan backward through the log
-        while let Some(entry) = iter.prev()?  {
-            match str::from_utf8(&entry) {
-            | Ok(entry) if !cache.contains(&*entry) => {
-                cache.insert(entry.to_owned());
+        self.for_each_entry(Direction::Backward, |kind, entry| {
+            match classify(kind, entry)? {
+            | Marker::BatchEnd(remaining) => {
+                batch_remaining = Some(remaining);
+                batch_entries.clear();
             }
-            | _ => (),
+            | Marker::BatchBegin if batch_remaining.take() == Some(0) => {
+                for entry in batch_entries.drain(..) {
+                    if cache.len() == count {
+                        break;
+                    }
+                    if cache.insert(entry.clone()) {
+                        f(&entry)?;
+                    }
+                }
             }
-            if cache.len() == count {
-                break;
+            | Marker::BatchBegin => return Ok(ControlFlow::Break(())), // unterminated or torn batch: discard and stop
+            | Marker::Entry => {
+                // Track the batch boundary regardless of whether this
+                // particular entry happens to be valid UTF-8: a non-UTF8
+                // entry still counts toward `remaining`, same as `recover`
+                // does, so a perfectly valid (but non-text) batch member
+                // doesn't throw off where the begin marker is expected.
+                if let Some(remaining) = batch_remaining.as_mut() {
+                    *remaining = remaining.saturating_sub(1);
+                }
+
+                let entry = match str::from_utf8(entry) {
+                | Ok(entry) => entry,
+                | Err(_) => return Ok(ControlFlow::Continue(())),
+                };
+
+                match batch_remaining {
+                | Some(_) => batch_entries.push(entry.to_owned()),
+                | None if cache.len() < count && cache.insert(entry.to_owned()) => {
+                    f(entry)?;
+                }
+                | None => (),
+                }
             }
-        }
+            }
+
+            if batch_remaining.is_none() && cache.len() == count {
+                Ok(ControlFlow::Break(()))
+            } else {
+                Ok(ControlFlow::Continue(()))
+            }
+        })?;
 
         Ok(cache)
     }
 
+    /// Call `f` with every entry still in the log, in the order it was
+    /// written (oldest first), duplicates and all, skipping batch markers
+    /// and entries that aren't valid UTF-8.
+    ///
+    /// Unlike [`Log::entries`]/[`Log::for_each_unique_entry`], nothing is
+    /// deduplicated or limited to the most recent ones — this is a forward
+    /// replay of everything the log file holds. Compaction rewrites the log
+    /// with only the retained entries, so entries it dropped are not
+    /// replayed.
+    pub fn for_each_historical_entry(&mut self, mut f: impl FnMut(&str) -> io::Result<()>) -> anyhow::Result<()> {
+        self.recover()?;
+
+        self.for_each_entry(Direction::Forward, |kind, entry| {
+            if !matches!(classify(kind, entry)?, Marker::Entry) {
+                return Ok(ControlFlow::Continue(()));
+            }
+
+            if let Ok(entry) = str::from_utf8(entry) {
+                f(entry)?;
+            }
+
+            Ok(ControlFlow::Continue(()))
+        })?;
+
+        Ok(())
+    }
+
+    /// Scan backward from the end of the log, discarding any trailing bytes
+    /// that do not form a complete, checksummed entry.
+    ///
+    /// Stops at the first entry whose length field points past the start of
+    /// the file or whose CRC32 does not match its payload, and truncates the
+    /// file to the end of the last good entry before it. An unterminated
+    /// trailing batch (a begin marker with no matching end marker) is
+    /// treated the same way: everything from its begin marker onward is
+    /// discarded. Returns the number of bytes discarded.
+    pub fn recover(&mut self) -> anyhow::Result<usize> {
+        let original_len = self.file
+            .metadata()
+            .with_context(|| anyhow!("Could not read log file metadata: `{}`", self.path.display()))?
+            .len();
+
+        let mut batch_remaining: Option<u32> = None;
+
+        let stopped_at = self.for_each_entry(Direction::Backward, |kind, entry| {
+            match classify(kind, entry)? {
+            | Marker::BatchEnd(remaining) => batch_remaining = Some(remaining),
+            | Marker::BatchBegin if batch_remaining.take() == Some(0) => (),
+            | Marker::BatchBegin => return Ok(ControlFlow::Break(())),
+            | Marker::Entry => if let Some(remaining) = batch_remaining.as_mut() {
+                *remaining = remaining.saturating_sub(1);
+            },
+            }
+
+            Ok(ControlFlow::Continue(()))
+        })?;
+
+        let good = stopped_at.unwrap_or(original_len);
+        let discarded = original_len - good;
+
+        if discarded > 0 {
+            try_with_context! {
+                CONTEXT: anyhow!("Could not truncate log file: `{}`", self.path.display());
+                self.file.set_len(good)?;
+                let _ = self.file.seek(io::SeekFrom::End(0))?;
+            }?;
+        }
+
+        Ok(discarded as usize)
+    }
+
     /// Return the number of bytes between the beginning of the log file and
     /// the current seek position.
     pub fn position(&mut self) -> anyhow::Result<u64> {
@@ -129,16 +494,183 @@ impl Log {
     /// [it]: https://doc.rust-lang.org/std/iter/trait.Iterator.html
     /// [gat]: https://github.com/rust-lang/rfcs/blob/master/text/1598-generic_associated_types.md
     fn iter(&mut self) -> Iter<'_> {
+        let pos = self.file
+            .seek(io::SeekFrom::End(0))
+            .map(|pos| pos.max(HEADER_LEN))
+            .unwrap_or(HEADER_LEN);
+
         Iter {
             buf: Vec::new(),
-            pos: self.file
-                .seek(io::SeekFrom::End(-2))
-                .unwrap_or(0),
+            pos,
             log: &mut self.file
         }
     }
 }
 
+/// Frame kind tagging an ordinary cache entry.
+const KIND_ENTRY: u8 = 0;
+
+/// Frame kind tagging a batch's begin marker. Its payload is always empty.
+const KIND_BATCH_BEGIN: u8 = 1;
+
+/// Frame kind tagging a batch's end marker. Its payload is always a
+/// little-endian `u32` count of the entries the batch covers.
+const KIND_BATCH_END: u8 = 2;
+
+/// Classification of a decoded entry, distinguishing batch markers from
+/// ordinary cache entries.
+enum Marker {
+    BatchBegin,
+    BatchEnd(u32),
+    Entry,
+}
+
+/// Classify a decoded entry as a batch marker or an ordinary entry, based on
+/// the `kind` its frame was written with.
+///
+/// `kind` lives outside the entry's payload (see [`write_entry`]), so unlike
+/// an earlier scheme that recognized markers by magic payload bytes, a real
+/// entry can never be mistaken for one: an entry put with `--batch` whose
+/// contents happen to equal a marker's old magic bytes round-trips as an
+/// ordinary entry instead of corrupting the batch scan.
+fn classify(kind: u8, payload: &[u8]) -> io::Result<Marker> {
+    match kind {
+    | KIND_ENTRY => Ok(Marker::Entry),
+    | KIND_BATCH_BEGIN => Ok(Marker::BatchBegin),
+    | KIND_BATCH_END => {
+        let count: [u8; 4] = payload
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed batch end marker"))?;
+        Ok(Marker::BatchEnd(u32::from_le_bytes(count)))
+    }
+    | _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown log entry kind")),
+    }
+}
+
+/// Write `payload` framed as `[payload][crc32][kind][len]`, with no limit on
+/// `payload`'s size.
+fn write_entry(file: &mut fs::File, kind: u8, payload: &[u8]) -> io::Result<()> {
+    file.write_all(payload)?;
+    file.write_u32::<LittleEndian>(crc32fast::hash(payload))?;
+    file.write_u8(kind)?;
+    write_varint_len(file, payload.len() as u64)
+}
+
+/// Write `len` as a base-128 varint whose groups are ordered so that reading
+/// the bytes back to front (as [`read_varint_len`] does) yields the
+/// least-significant group first.
+///
+/// Unlike a standard forward-read LEB128 varint, the *first* byte written
+/// (the most-significant group, adjacent to the payload) carries a clear
+/// continuation bit to mark where a backward reader should stop; every byte
+/// written after it, up to and including the last (least-significant) byte
+/// at the end of the file, carries the continuation bit set.
+fn write_varint_len(file: &mut fs::File, len: u64) -> io::Result<()> {
+    let mut groups = Vec::with_capacity(std::mem::size_of::<u64>());
+    let mut remainder = len;
+
+    loop {
+        groups.push((remainder & 0x7f) as u8);
+        remainder >>= 7;
+        if remainder == 0 {
+            break;
+        }
+    }
+
+    for (i, group) in groups.into_iter().rev().enumerate() {
+        let byte = if i == 0 { group } else { group | VARINT_CONTINUE };
+        file.write_u8(byte)?;
+    }
+
+    Ok(())
+}
+
+/// Read a [`write_varint_len`]-encoded length by walking backward from
+/// `end`, one byte at a time, until the terminator byte (continuation bit
+/// clear) is found. Returns the decoded length and the number of bytes the
+/// encoding occupied.
+fn read_varint_len(file: &mut fs::File, end: u64) -> io::Result<(u64, u64)> {
+    let mut value: u64 = 0;
+    let mut shift: u32 = 0;
+    let mut at = end;
+
+    loop {
+        at = at
+            .checked_sub(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated varint length"))?;
+        file.seek(io::SeekFrom::Start(at))?;
+        let byte = file.read_u8()?;
+
+        if shift >= 64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "overlong varint length"));
+        }
+
+        value |= u64::from(byte & !VARINT_CONTINUE) << shift;
+        shift += 7;
+
+        if byte & VARINT_CONTINUE == 0 {
+            break;
+        }
+    }
+
+    Ok((value, end - at))
+}
+
+/// Write the `[magic][version][flags]` header at the current (start-of-file)
+/// position.
+fn write_header(file: &mut fs::File) -> io::Result<()> {
+    file.write_all(MAGIC)?;
+    file.write_u8(VERSION)?;
+    file.write_u8(FLAG_CRC32)?;
+    file.flush()?;
+    file.sync_data()
+}
+
+/// Read and validate the header of an existing, non-empty log file.
+fn read_header(file: &mut fs::File) -> anyhow::Result<()> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.seek(io::SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+
+    if &header[..MAGIC.len()] != &MAGIC[..] {
+        return Err(anyhow!("not a goldfish log file"));
+    }
+
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        return Err(anyhow!("unsupported log format version {} (expected {})", version, VERSION));
+    }
+
+    Ok(())
+}
+
+/// Error returned while iterating over a log whose on-disk framing does not
+/// check out, either because of an I/O failure or a CRC32 mismatch.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The entry's frame can't be located or its stored CRC32 does not
+    /// match its payload.
+    Corrupt { offset: u64 },
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+        | Error::Io(error) => write!(fmt, "{}", error),
+        | Error::Corrupt { offset } => write!(fmt, "corrupt log entry at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 /// Implements a reverse iterator over the underlying log file.
 pub struct Iter<'h> {
     buf: Vec<u8>,
@@ -147,39 +679,178 @@ pub struct Iter<'h> {
 }
 
 impl<'h> Iter<'h> {
-    /// Read the previous log entry.
-    pub fn prev(&mut self) -> io::Result<Option<&[u8]>> {
-        if self.pos == 0 {
+    /// Read the previous log entry, verifying its CRC32.
+    ///
+    /// Frame layout is `[payload][crc32:u32][kind:u8][len:varint]`, so
+    /// `self.pos` (the end of the entry we're about to read) is used to
+    /// decode the trailing varint first; its width then locates the kind
+    /// byte, the CRC32, and the payload. Returns the frame's kind alongside
+    /// its payload so callers can tell batch markers from ordinary entries
+    /// without inspecting the payload itself (see [`classify`]).
+    pub fn prev(&mut self) -> Result<Option<(u8, &[u8])>, Error> {
+        if self.pos <= HEADER_LEN {
             return Ok(None);
         }
 
-        // |   /|0x01|0x00|   /|   b|   a|   r|0x04|0x00|
-        //                                    ^
+        let entry_end = self.pos;
+        let (len, varint_len) = match read_varint_len(self.log, entry_end) {
+        | Ok(decoded) => decoded,
+        | Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+            self.pos = HEADER_LEN;
+            return Err(Error::Corrupt { offset: entry_end });
+        }
+        | Err(error) => return Err(Error::Io(error)),
+        };
 
-        let len = self.log.read_u16::<LittleEndian>()?;
+        let kind_start = match entry_end.checked_sub(varint_len + 1) {
+        | Some(kind_start) => kind_start,
+        | None => {
+            // Can't locate this entry's frame at all: nothing past it is
+            // trustworthy either, so leave no margin for recovery to keep.
+            self.pos = HEADER_LEN;
+            return Err(Error::Corrupt { offset: entry_end });
+        }
+        };
+
+        let crc_start = match kind_start.checked_sub(4) {
+        | Some(crc_start) => crc_start,
+        | None => {
+            self.pos = HEADER_LEN;
+            return Err(Error::Corrupt { offset: entry_end });
+        }
+        };
 
-        // |   /|0x01|0x00|   /|   b|   a|   r|0x04|0x00|
-        //                                              ^
+        let payload_start = match crc_start.checked_sub(len).filter(|&start| start >= HEADER_LEN) {
+        | Some(payload_start) => payload_start,
+        | None => {
+            self.pos = HEADER_LEN;
+            return Err(Error::Corrupt { offset: entry_end });
+        }
+        };
+        let len = len as usize;
 
-        self.log.seek(io::SeekFrom::Current(-2 - (len as i64)))?;
+        // The frame itself checks out, so whatever happens next, this is
+        // where the *previous* entry ends — update `pos` before validating
+        // the CRC so a failed [`Log::recover`] scan still knows exactly
+        // where to truncate.
+        self.pos = payload_start;
 
-        // |   /|0x01|0x00|   /|   b|   a|   r|0x04|0x00|
-        //                ^
+        self.log.seek(io::SeekFrom::Start(kind_start))?;
+        let kind = self.log.read_u8()?;
 
+        self.log.seek(io::SeekFrom::Start(payload_start))?;
         self.buf.clear();
-        self.buf.resize(len as usize, 0);
+        self.buf.resize(len, 0);
         self.log.read_exact(&mut self.buf[..])?;
 
-        // |   /|0x01|0x00|   /|   b|   a|   r|0x04|0x00|
-        //                                    ^
+        self.log.seek(io::SeekFrom::Start(crc_start))?;
+        let crc = self.log.read_u32::<LittleEndian>()?;
+
+        if crc32fast::hash(&self.buf) != crc {
+            return Err(Error::Corrupt { offset: entry_end });
+        }
+
+        Ok(Some((kind, &self.buf)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// A path under the system temp directory unique to this test process
+    /// and call, cleaned up by the caller when done.
+    fn temp_path(name: &str) -> path::PathBuf {
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut path = std::env::temp_dir();
+        path.push(format!("goldfish-test-{}-{}-{}.log", std::process::id(), name, id));
+        path
+    }
+
+    #[test]
+    fn varint_len_round_trips_across_the_128_boundary() {
+        let path = temp_path("varint-round-trip");
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        // 127 fits in a single varint group (no continuation bit needed);
+        // 128 is the smallest value that needs a second group.
+        for len in [0, 1, 126, 127, 128, 129, u16::MAX as u64] {
+            let start = file.seek(io::SeekFrom::End(0)).unwrap();
+            write_varint_len(&mut file, len).unwrap();
+            let end = file.seek(io::SeekFrom::End(0)).unwrap();
+
+            let (decoded, width) = read_varint_len(&mut file, end).unwrap();
+
+            assert_eq!(decoded, len);
+            assert_eq!(start + width, end);
+        }
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn batch_entries_colliding_with_old_marker_bytes_round_trip() {
+        let path = temp_path("batch-marker-collision");
+        let mut log = Log::load(path.clone()).unwrap();
+
+        // These payloads used to alias the begin/end marker bytes under the
+        // old magic-payload scheme; since markers are now tagged out of
+        // band by frame kind, they must round-trip like any other entry.
+        log.append_batch(vec![
+            b"a".to_vec(),
+            vec![0x00, 0x00],
+            vec![0x00, 0x01, 0, 0, 0, 0],
+            b"b".to_vec(),
+        ])
+        .unwrap();
+
+        let entries = log.entries(10).unwrap();
+
+        assert_eq!(entries.len(), 4);
+        assert!(entries.contains("a"));
+        assert!(entries.contains("b"));
+        assert!(entries.contains(&String::from_utf8(vec![0x00, 0x00]).unwrap()));
+        assert!(entries.contains(&String::from_utf8(vec![0x00, 0x01, 0, 0, 0, 0]).unwrap()));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn recover_truncates_at_first_crc_mismatch() {
+        let path = temp_path("recover-crc-mismatch");
+        let mut log = Log::load(path.clone()).unwrap();
+
+        log.append("keep-me").unwrap();
+        log.sync().unwrap();
+        let good_len = fs::metadata(&path).unwrap().len();
+
+        log.append("corrupt-me").unwrap();
+        log.sync().unwrap();
+        let corrupt_len = fs::metadata(&path).unwrap().len();
+
+        // Flip the first payload byte of the trailing entry so its CRC32 no
+        // longer matches.
+        {
+            let mut raw = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            raw.seek(io::SeekFrom::Start(good_len)).unwrap();
+            raw.write_all(&[b'c' ^ 0xff]).unwrap();
+        }
+
+        let discarded = log.recover().unwrap();
 
-        self.pos = self.log
-            .seek(io::SeekFrom::Current(-2 - (len as i64)))
-            .unwrap_or(0);
+        assert_eq!(discarded as u64, corrupt_len - good_len);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
 
-        // |   /|0x01|0x00|   /|   b|   a|   r|0x04|0x00|
-        //      ^
-        
-        Ok(Some(&self.buf))
+        let _ = fs::remove_file(&path);
     }
 }
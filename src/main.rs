@@ -1,5 +1,6 @@
 use std::ffi;
 use std::io;
+use std::io::BufRead;
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
 use std::str;
@@ -38,8 +39,21 @@ enum Command {
         /// If this is zero, then the file will be deleted
         #[structopt(default_value = "256")]
         count: usize,
+
+        /// Compact by truncating and re-appending in place instead of
+        /// rewriting atomically through a temp file
+        ///
+        /// Faster, but a crash mid-compaction can lose the entire cache.
+        #[structopt(long)]
+        fast: bool,
     },
 
+    /// Discard any trailing corrupt or incomplete entries
+    Recover,
+
+    /// Print every entry still in the log, oldest first, duplicates included
+    History,
+
     /// Print all newline-separated cache entries
     Get {
         /// Maximum bytes of stale cache entries before compaction
@@ -49,6 +63,13 @@ enum Command {
         /// Maximum cache entries to print
         #[structopt(default_value = "256")]
         count: usize,
+
+        /// Compact by truncating and re-appending in place instead of
+        /// rewriting atomically through a temp file
+        ///
+        /// Faster, but a crash mid-compaction can lose the entire cache.
+        #[structopt(long)]
+        fast: bool,
     },
 
     /// Update the cache with `entry` as the most recent
@@ -57,8 +78,14 @@ enum Command {
         #[structopt(short, long)]
         r#type: Option<Type>,
 
+        /// Read newline-separated entries from `stdin` and commit them as a
+        /// single atomic batch instead of taking `entry` on the command line
+        #[structopt(short, long)]
+        batch: bool,
+
         /// Cache entry to put
-        entry: String,
+        #[structopt(required_unless = "batch")]
+        entry: Option<String>,
     },
 }
 
@@ -109,16 +136,20 @@ fn main() -> anyhow::Result<()> {
     let log = log::Log::load(path)?;
 
     match opt.command {
-    | Command::Clean { count } => clean(log, count, None)
+    | Command::Clean { count, fast } => clean(log, count, None, fast)
         .context("Could not clean cache"),
-    | Command::Get { count, threshold } => get(log, count, threshold)
+    | Command::Recover => recover(log)
+        .context("Could not recover cache"),
+    | Command::History => history(log)
+        .context("Could not print cache history"),
+    | Command::Get { count, threshold, fast } => get(log, count, threshold, fast)
         .context("Could not get cache contents"),
-    | Command::Put { r#type, entry } => put(log, r#type, entry)
+    | Command::Put { r#type, batch, entry } => put(log, r#type, batch, entry)
         .context("Could not put cache entry"),
     }
 }
 
-fn clean(mut log: log::Log, count: usize, entries: Option<IndexSet<String>>) -> anyhow::Result<()> {
+fn clean(mut log: log::Log, count: usize, entries: Option<IndexSet<String>>, fast: bool) -> anyhow::Result<()> {
     if count == 0 {
         return log.delete();
     }
@@ -128,44 +159,82 @@ fn clean(mut log: log::Log, count: usize, entries: Option<IndexSet<String>>) ->
     | Some(entries) => entries,
     };
 
-    log.clear()?;
-    entries.into_iter()
-        .rev()
-        .try_for_each(|entry| log.append(entry))?;
-    log.sync()
+    let entries = entries.into_iter().rev();
+
+    if fast {
+        log.compact_in_place(entries)
+    } else {
+        log.compact(entries)
+    }
+}
+
+fn recover(mut log: log::Log) -> anyhow::Result<()> {
+    let discarded = log.recover()?;
+    if discarded > 0 {
+        eprintln!("Discarded {} corrupt byte(s)", discarded);
+    }
+    Ok(())
 }
 
-fn get(mut log: log::Log, count: usize, threshold: u64) -> anyhow::Result<()> {
+fn history(mut log: log::Log) -> anyhow::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    log.for_each_historical_entry(|entry| writeln!(&mut stdout, "{}", entry))?;
+    stdout.flush()?;
+    Ok(())
+}
 
-    let entries = log.entries(count)?;
+fn get(mut log: log::Log, count: usize, threshold: u64, fast: bool) -> anyhow::Result<()> {
 
-    // Write to `stdout`
+    // Stream entries to `stdout` as they're found instead of buffering the
+    // whole result set first
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
-    for entry in &entries {
-        writeln!(&mut stdout, "{}", entry)?;
-    }
+    let entries = log.for_each_unique_entry(count, |entry| writeln!(&mut stdout, "{}", entry))?;
     stdout.flush()?;
     drop(stdout);
 
     // Compact the log by rewriting only the relevant entries
     if log.position()? > threshold {
-        clean(log, count, Some(entries))?;
+        clean(log, count, Some(entries), fast)?;
     }
 
     Ok(())
 }
 
-fn put(mut log: log::Log, r#type: Option<Type>, entry: String) -> anyhow::Result<()> {
-    let entry = match r#type {
-    | None => ffi::OsString::from(entry),
+fn put(mut log: log::Log, r#type: Option<Type>, batch: bool, entry: Option<String>) -> anyhow::Result<()> {
+    if batch {
+        let stdin = io::stdin();
+        let entries = stdin
+            .lock()
+            .lines()
+            .collect::<io::Result<Vec<_>>>()
+            .context("Could not read batch entries from stdin")?
+            .into_iter()
+            .filter_map(|entry| transform(r#type, entry))
+            .collect::<Vec<_>>();
+
+        return log.append_batch(entries.iter().map(|entry| entry.as_bytes()));
+    }
+
+    let entry = entry.expect("`entry` is required unless `--batch` is set");
+    match transform(r#type, entry) {
+    | None => Ok(()),
+    | Some(entry) => {
+        log.append(entry.as_bytes())?;
+        log.sync()
+    }
+    }
+}
+
+fn transform(r#type: Option<Type>, entry: String) -> Option<ffi::OsString> {
+    match r#type {
+    | None => Some(ffi::OsString::from(entry)),
     | Some(r#type) => {
         match path::Path::new(&entry).canonicalize() {
-        | Ok(path) if r#type.validate(&path) => path.into_os_string(),
-        | Ok(_) | Err(_) => return Ok(()),
+        | Ok(path) if r#type.validate(&path) => Some(path.into_os_string()),
+        | Ok(_) | Err(_) => None,
         }
     }
-    };
-    log.append(entry.as_bytes())?;
-    log.sync()
+    }
 }